@@ -1,13 +1,16 @@
 use std::env;
 use std::io::Write;
+use std::path::Path;
 use std::process::Command;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 use failure::{Error, ResultExt};
+use serde_json;
 use termcolor::{ColorSpec, StandardStream, WriteColor};
 
 use super::exit_with;
 use diagnostics::{self, log_for_human, output_stream, write_warning, Message};
+use edition::{self, Edition};
 use lock;
 use vcs::VersionControl;
 
@@ -68,8 +71,13 @@ pub fn run() -> Result<(), Error> {
         )
         .arg(
             Arg::with_name("edition")
+                .long("edition")
+                .help("Fix warnings in preparation of the next edition upgrade"),
+        )
+        .arg(
+            Arg::with_name("prepare-for")
                 .long("prepare-for")
-                .help("Fix warnings in preparation of an edition upgrade")
+                .help("Deprecated, use `--edition` instead")
                 .takes_value(true)
                 .possible_values(&["2018"]),
         )
@@ -83,6 +91,11 @@ pub fn run() -> Result<(), Error> {
                 .long("allow-dirty")
                 .help("Fix code even if the working directory is dirty"),
         )
+        .arg(
+            Arg::with_name("fix-deps")
+                .long("fix-deps")
+                .help("Also fix code in path dependencies outside the current package"),
+        )
         .after_help("\
 This Cargo subcommmand will automatically take rustc's suggestions from
 diagnostics like warnings and apply them to your source code. This is intended
@@ -161,13 +174,25 @@ https://github.com/rust-lang-nursery/rustfix
 
     check_version_control(matches)?;
 
+    // Figure out ahead of time whether we're in edition-upgrade mode, and if
+    // so bail out early with a clear error/warning rather than letting the
+    // subprocesses silently do nothing.
+    let edition_upgrade = matches.is_present("edition") || matches.is_present("prepare-for");
+    let current_edition = if edition_upgrade {
+        let edition = edition::detect_current_edition(&manifest_path(matches)?)?;
+        check_edition_transition(matches, edition)?;
+        Some(edition)
+    } else {
+        None
+    };
+
     // Spin up our lock server which our subprocesses will use to synchronize
     // fixes.
     let _lock_server = lock::Server::new()?.start()?;
 
     // Spin up our diagnostics server which our subprocesses will use to send
     // use their dignostics messages in an ordered way.
-    let _diagnostics_server = diagnostics::Server::new()?.start(|m, stream| {
+    let diagnostics_server = diagnostics::Server::new()?.start(|m, stream| {
         if let Err(e) = log_message(&m, stream) {
             warn!("failed to log message: {}", e);
         }
@@ -218,21 +243,45 @@ https://github.com/rust-lang-nursery/rustfix
         cmd.env("RUSTC_ORIGINAL", rustc);
     }
 
-    // Trigger edition-upgrade mode. Currently only supports the 2018 edition.
-    info!("edition upgrade? {:?}", matches.value_of("edition"));
-    if let Some("2018") = matches.value_of("edition") {
-        info!("edition upgrade!");
+    // By default only apply fixes to the "primary" packages being worked on
+    // (those named via `-p`, all workspace members if `--all` was passed, or
+    // the package in the current directory if neither was) so that
+    // `cargo fix` doesn't rewrite source in path dependencies the user
+    // didn't intend to touch. Only `--fix-deps` actually opts into the old
+    // fix-everything-including-external-path-deps behavior.
+    if !matches.is_present("fix-deps") {
+        let primary = primary_packages(matches)?;
+        cmd.env("__CARGO_FIX_PRIMARY_PACKAGES", primary.join(","));
+    }
+
+    // Trigger edition-upgrade mode, either via the new `--edition` flag or
+    // the deprecated `--prepare-for <edition>` alias. Either way we already
+    // validated the transition above, so just derive the lint group from the
+    // edition we detected and let the subprocesses know they're in this mode
+    // (so they can double check per-crate in a workspace).
+    info!("edition upgrade? {}", edition_upgrade);
+    if let Some(current_edition) = current_edition {
+        let lint_group = current_edition
+            .lint_group()
+            .expect("validated above that there's a next edition to migrate to");
+        info!("edition upgrade! lint group: {}", lint_group);
         let mut rustc_flags = env::var_os("RUSTFLAGS").unwrap_or_else(|| "".into());
-        rustc_flags.push(" -W rust-2018-compatibility");
+        rustc_flags.push(format!(" -W {}", lint_group));
         cmd.env("RUSTFLAGS", &rustc_flags);
+        cmd.env("__CARGO_FIX_EDITION_UPGRADE", "1");
     }
 
     // An now execute all of Cargo! This'll fix everything along the way.
-    //
-    // TODO: we probably want to do something fancy here like collect results
-    // from the client processes and print out a summary of what happened.
     let status = cmd.status()
         .with_context(|e| format!("failed to execute `{}`: {}", cargo.to_string_lossy(), e))?;
+
+    // `cmd.status()` only waits for the `cargo` child to exit, not for our
+    // own background thread to finish folding in whatever its last
+    // subprocesses reported, so explicitly drain it before reading the
+    // summary.
+    let summary = diagnostics_server.finish();
+    diagnostics::print_summary(&summary, &mut output_stream())?;
+
     exit_with(status);
 }
 
@@ -278,12 +327,138 @@ fn check_version_control(matches: &::clap::ArgMatches) -> Result<(), Error> {
     Ok(())
 }
 
+/// Resolves the `--manifest-path` the user passed (or, like real `cargo`,
+/// the nearest `Cargo.toml` found by searching upward from the current
+/// directory) into a `PathBuf` we can hand to the `edition` module.
+fn manifest_path(matches: &::clap::ArgMatches) -> Result<::std::path::PathBuf, Error> {
+    if let Some(path) = matches.value_of("manifest-path") {
+        return Ok(Path::new(path).to_owned());
+    }
+
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(&cargo)
+        .arg("locate-project")
+        .arg("--message-format=plain")
+        .output()
+        .with_context(|e| format!("failed to run `{} locate-project`: {}", cargo.to_string_lossy(), e))?;
+    if !output.status.success() {
+        bail!(
+            "failed to locate `Cargo.toml`: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let path = String::from_utf8(output.stdout).context("`cargo locate-project` produced non-UTF8 output")?;
+    Ok(Path::new(path.trim()).to_owned())
+}
+
+/// The set of packages that should actually have fixes applied to them: the
+/// ones named via `-p`/`--package`, every workspace member if `--all` was
+/// passed, or (if neither was) the package rooted at
+/// `--manifest-path`/the current directory.
+fn primary_packages(matches: &::clap::ArgMatches) -> Result<Vec<String>, Error> {
+    if let Some(packages) = matches.values_of("package") {
+        return Ok(packages.map(String::from).collect());
+    }
+
+    if matches.is_present("all") {
+        return workspace_member_names(matches);
+    }
+
+    Ok(vec![package_name(&manifest_path(matches)?)?])
+}
+
+/// Names of every workspace member crate, by asking `cargo metadata` rather
+/// than hand-parsing the (possibly virtual) root manifest's `[workspace]`
+/// table. `--no-deps` keeps this limited to workspace members, excluding
+/// external path dependencies outside the workspace.
+fn workspace_member_names(matches: &::clap::ArgMatches) -> Result<Vec<String>, Error> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut cmd = Command::new(&cargo);
+    cmd.arg("metadata").arg("--no-deps").arg("--format-version=1");
+    if let Some(path) = matches.value_of("manifest-path") {
+        cmd.arg("--manifest-path").arg(path);
+    }
+
+    let output = cmd
+        .output()
+        .with_context(|e| format!("failed to run `{} metadata`: {}", cargo.to_string_lossy(), e))?;
+    if !output.status.success() {
+        bail!(
+            "failed to run `cargo metadata`: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .context("`cargo metadata` produced output we couldn't parse")?;
+    let names = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|package| package["name"].as_str().map(String::from))
+        .collect();
+    Ok(names)
+}
+
+/// Reads the `name` key out of a manifest's `[package]` section.
+fn package_name(manifest_path: &Path) -> Result<String, Error> {
+    edition::read_manifest_key(manifest_path, "name")?
+        .ok_or_else(|| format_err!("no `name` key found in `{}`", manifest_path.display()))
+}
+
+/// Guards against the two common misuses of edition-upgrade mode: preparing
+/// for an edition the crate is already on (the migration lints can never
+/// fire, so we'd otherwise silently do nothing), and forgetting to enable
+/// the next edition's preview feature (same problem, quieter failure mode).
+fn check_edition_transition(matches: &::clap::ArgMatches, current: Edition) -> Result<(), Error> {
+    let manifest_path = manifest_path(matches)?;
+
+    // A virtual workspace manifest has no `[package]`/`src` of its own, so
+    // neither check below is meaningful against it: `current` is only
+    // `detect_current_edition`'s E2015 fallback, not a real answer, and
+    // there's no crate root to look for a preview-feature gate in. Defer
+    // entirely to the per-crate check each rustc-proxy subprocess already
+    // does independently (see `fix::check_edition_already_set`).
+    if edition::is_virtual_manifest(&manifest_path)? {
+        return Ok(());
+    }
+
+    if current.next().is_none() {
+        bail!(
+            "crate is already on the newest edition we know how to migrate \
+             from, there's nothing to prepare for"
+        );
+    }
+
+    if !edition::has_preview_feature_enabled(&manifest_path, current) {
+        let stream = &mut output_stream();
+
+        write_warning(stream)?;
+        stream.set_color(ColorSpec::new().set_bold(true))?;
+        writeln!(stream, "next-edition preview feature not enabled")?;
+        stream.reset()?;
+        writeln!(
+            stream,
+            "Add `#![feature({})]` to your crate root, otherwise the \
+             edition-migration lints won't fire and this run won't find \
+             anything to fix.",
+            current
+                .preview_feature()
+                .expect("checked above that there's a next edition"),
+        )?;
+    }
+
+    Ok(())
+}
+
 fn log_message(msg: &Message, stream: &mut StandardStream) -> Result<(), Error> {
     use diagnostics::Message::*;
 
     match *msg {
         Fixing {
             ref file,
+            krate: _,
             ref fixes,
         } => {
             log_for_human(
@@ -299,6 +474,7 @@ fn log_message(msg: &Message, stream: &mut StandardStream) -> Result<(), Error>
         }
         ReplaceFailed {
             ref file,
+            krate: _,
             ref message,
         } => {
             write_warning(stream)?;
@@ -340,9 +516,79 @@ fn log_message(msg: &Message, stream: &mut StandardStream) -> Result<(), Error>
             }
             stream.write(PLEASE_REPORT_THIS_BUG.as_bytes())?;
         }
+        EditionAlreadySet { ref krate } => {
+            write_warning(stream)?;
+            stream.set_color(ColorSpec::new().set_bold(true))?;
+            write!(
+                stream,
+                "crate `{}` is already on the edition being prepared for\n",
+                krate,
+            )?;
+            stream.reset()?;
+            write!(
+                stream,
+                "none of the migration lints could have fired for it in this run\n"
+            )?;
+        }
     }
 
     stream.reset()?;
     stream.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Builds the subset of `ArgMatches` that `primary_packages` and
+    /// `manifest_path` look at, without going through the full `run()` CLI.
+    fn matches(args: &[&str]) -> ::clap::ArgMatches<'static> {
+        App::new("fix")
+            .arg(Arg::with_name("package").long("package").short("p").multiple(true).takes_value(true))
+            .arg(Arg::with_name("manifest-path").long("manifest-path").takes_value(true))
+            .get_matches_from(args)
+    }
+
+    fn temp_manifest(contents: &str) -> ::std::path::PathBuf {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let path = env::temp_dir().join(format!(
+            "cargo-fix-cli-test-{}-{}.toml",
+            ::std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn primary_packages_uses_named_packages_when_given() {
+        let matches = matches(&["fix", "-p", "foo", "-p", "bar"]);
+        assert_eq!(
+            primary_packages(&matches).unwrap(),
+            vec!["foo".to_string(), "bar".to_string()]
+        );
+    }
+
+    #[test]
+    fn primary_packages_falls_back_to_the_manifest_name() {
+        let manifest = temp_manifest("[package]\nname = \"foo\"\n");
+        let matches = matches(&["fix", "--manifest-path", manifest.to_str().unwrap()]);
+        assert_eq!(primary_packages(&matches).unwrap(), vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn package_name_reads_the_package_table() {
+        let manifest = temp_manifest("[lib]\nname = \"not-the-crate\"\n\n[package]\nname = \"foo\"\n");
+        assert_eq!(package_name(&manifest).unwrap(), "foo");
+    }
+
+    #[test]
+    fn package_name_errors_without_a_name_key() {
+        let manifest = temp_manifest("[package]\nedition = \"2018\"\n");
+        assert!(package_name(&manifest).is_err());
+    }
+}
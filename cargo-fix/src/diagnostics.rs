@@ -0,0 +1,416 @@
+//! A small IPC mechanism used to ferry diagnostic messages from the rustc
+//! subprocesses spawned by `cargo fix` (running as `__CARGO_FIX_NOW_RUSTC`)
+//! back to the main `cargo fix` process, which is responsible for rendering
+//! them to the user in a sensible, serialized order.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use failure::{Error, ResultExt};
+use serde_json;
+use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
+
+const ENV: &str = "__CARGO_FIX_DIAGNOSTICS_ENDPOINT";
+
+/// A line sent by `ServerHandle::finish` to tell the background thread to
+/// stop accepting connections, once it's drained everything already queued.
+/// Not a valid `Message`, so it can share the same line-based protocol.
+const SHUTDOWN_SENTINEL: &str = "__shutdown__";
+
+/// Messages sent from a rustc-proxy subprocess to the main `cargo fix`
+/// process while a fix is in progress.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Message {
+    /// A file was successfully fixed.
+    Fixing {
+        file: String,
+        krate: Option<String>,
+        fixes: u32,
+    },
+    /// Applying suggestions to a file failed outright (e.g. the file couldn't
+    /// be read or written back). Individual suggestions that don't apply
+    /// cleanly are skipped rather than reported here.
+    ReplaceFailed {
+        file: String,
+        krate: Option<String>,
+        message: String,
+    },
+    /// Suggestions were applied but the result didn't compile, so they were
+    /// backed out.
+    FixFailed {
+        files: Vec<String>,
+        krate: Option<String>,
+    },
+    /// A subprocess noticed that the crate it was compiling is already on
+    /// the edition being prepared for, so the migration lints driving this
+    /// run could never have fired for it.
+    EditionAlreadySet { krate: String },
+}
+
+/// Accumulated state built up over the course of a `cargo fix` run, used to
+/// print an end-of-run summary once the underlying `cargo check` exits.
+#[derive(Default)]
+pub struct Summary {
+    /// Total number of suggestions successfully applied, across all files.
+    pub fixes_applied: u32,
+    /// Every file that had at least one suggestion applied to it.
+    pub files_fixed: Vec<String>,
+    /// Number of fixes applied, keyed by crate name (`None` when we don't
+    /// know which crate a file belonged to).
+    pub fixes_by_crate: HashMap<Option<String>, u32>,
+    /// Crates where fixing was attempted but backed out or errored.
+    pub crates_failed: Vec<String>,
+}
+
+impl Summary {
+    fn record(&mut self, msg: &Message) {
+        match *msg {
+            Message::Fixing {
+                ref file,
+                ref krate,
+                fixes,
+            } => {
+                self.fixes_applied += fixes;
+                self.files_fixed.push(file.clone());
+                *self.fixes_by_crate.entry(krate.clone()).or_insert(0) += fixes;
+            }
+            Message::ReplaceFailed { ref krate, .. } => {
+                if let Some(ref krate) = *krate {
+                    self.crates_failed.push(krate.clone());
+                }
+            }
+            Message::FixFailed { ref krate, .. } => {
+                if let Some(ref krate) = *krate {
+                    self.crates_failed.push(krate.clone());
+                }
+            }
+            Message::EditionAlreadySet { .. } => {}
+        }
+    }
+}
+
+/// A server which subprocesses connect to and stream `Message`s to. Messages
+/// are forwarded, one connection at a time, to the callback passed to
+/// `start` so that output from multiple subprocesses running concurrently
+/// doesn't get interleaved.
+pub struct Server {
+    listener: TcpListener,
+}
+
+/// A client used by a rustc-proxy subprocess to report messages back to the
+/// `Server` started by the parent `cargo fix` process.
+pub struct Client {
+    addr: String,
+}
+
+impl Server {
+    pub fn new() -> Result<Server, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("failed to bind TCP listener")?;
+        Ok(Server { listener })
+    }
+
+    /// Starts this server, spawning a background thread to accept and
+    /// process connections, forwarding received messages to `on_message` and
+    /// folding them into a shared `Summary`. Sets up the environment so that
+    /// `Client::new` in subprocesses we spawn will find this server.
+    ///
+    /// Returns a `ServerHandle`; call `finish()` on it once `cargo check`
+    /// has exited to drain any connections still queued and obtain the
+    /// final `Summary`, rather than reading the summary straight away (the
+    /// background thread processes messages asynchronously, so nothing
+    /// guarantees the last subprocess's messages have been folded in yet).
+    pub fn start(
+        self,
+        on_message: impl Fn(Message, &mut StandardStream) + Send + 'static,
+    ) -> Result<ServerHandle, Error> {
+        let addr = self.listener.local_addr()?;
+        env::set_var(ENV, addr.to_string());
+
+        let summary = Arc::new(Mutex::new(Summary::default()));
+        let summary_clone = summary.clone();
+
+        let join = thread::spawn(move || {
+            let mut stream = output_stream();
+            // The same crate source is often compiled under several
+            // targets/feature sets, so subprocesses frequently report the
+            // exact same `Message` more than once. Only act on the first
+            // occurrence of each distinct message so the user doesn't see
+            // (or get counted for) the same fix several times.
+            let mut seen = HashSet::new();
+            for conn in self.listener.incoming() {
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(_) => continue,
+                };
+                for line in BufReader::new(conn).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if line == SHUTDOWN_SENTINEL {
+                        return;
+                    }
+                    if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+                        if !seen.insert(msg.clone()) {
+                            continue;
+                        }
+                        summary_clone.lock().unwrap().record(&msg);
+                        on_message(msg, &mut stream);
+                    }
+                }
+            }
+        });
+
+        Ok(ServerHandle {
+            addr: addr.to_string(),
+            join,
+            summary,
+        })
+    }
+}
+
+/// A handle on a running `Server`. Dropping this without calling `finish()`
+/// leaves the background thread running for the remainder of the process,
+/// which is harmless since `cargo fix` is about to exit anyway, but means
+/// the `Summary` it's accumulating may still be missing trailing messages.
+pub struct ServerHandle {
+    addr: String,
+    join: JoinHandle<()>,
+    summary: Arc<Mutex<Summary>>,
+}
+
+impl ServerHandle {
+    /// Tells the background thread to stop accepting new connections, waits
+    /// for it to drain every connection already queued in front of that
+    /// shutdown signal (in particular, every message the just-exited
+    /// subprocesses sent), and returns the fully accumulated `Summary`.
+    pub fn finish(self) -> Summary {
+        if let Ok(mut conn) = TcpStream::connect(&self.addr) {
+            let _ = writeln!(conn, "{}", SHUTDOWN_SENTINEL);
+        }
+        let _ = self.join.join();
+
+        match Arc::try_unwrap(self.summary) {
+            Ok(summary) => summary.into_inner().unwrap(),
+            // Shouldn't happen once the background thread has joined, but
+            // fall back to cloning out of the shared state rather than
+            // panicking on a report that's "merely" a best-effort summary.
+            Err(shared) => {
+                let summary = shared.lock().unwrap();
+                Summary {
+                    fixes_applied: summary.fixes_applied,
+                    files_fixed: summary.files_fixed.clone(),
+                    fixes_by_crate: summary.fixes_by_crate.clone(),
+                    crates_failed: summary.crates_failed.clone(),
+                }
+            }
+        }
+    }
+}
+
+impl Client {
+    pub fn new() -> Option<Client> {
+        env::var(ENV).ok().map(|addr| Client { addr })
+    }
+
+    pub fn report(&self, msg: &Message) -> Result<(), Error> {
+        let mut conn =
+            TcpStream::connect(&self.addr).context("failed to connect to diagnostics server")?;
+        let line = serde_json::to_string(msg)?;
+        writeln!(conn, "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Returns a handle used to print to stderr with color, matching the rest of
+/// `cargo fix`'s output.
+pub fn output_stream() -> StandardStream {
+    StandardStream::stderr(termcolor::ColorChoice::Auto)
+}
+
+/// Writes a bold-yellow "warning:"-style prefix matching Cargo's own output.
+pub fn write_warning(stream: &mut StandardStream) -> Result<(), Error> {
+    stream.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)).set_bold(true))?;
+    write!(stream, "warning: ")?;
+    stream.reset()?;
+    Ok(())
+}
+
+/// Writes a bold-green `<status>` tag followed by `message`, matching
+/// Cargo's conventional "Compiling foo v0.1.0" style output.
+pub fn log_for_human(status: &str, message: &str, stream: &mut StandardStream) -> Result<(), Error> {
+    stream.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true))?;
+    write!(stream, "{:>12}", status)?;
+    stream.reset()?;
+    writeln!(stream, " {}", message)?;
+    Ok(())
+}
+
+/// Prints a digestible end-of-run report of everything `summary` has
+/// accumulated: how many fixes were applied and to how many files, a
+/// per-crate breakdown, and which crates (if any) failed to fix.
+pub fn print_summary(summary: &Summary, stream: &mut StandardStream) -> Result<(), Error> {
+    if summary.fixes_applied == 0 && summary.crates_failed.is_empty() {
+        return Ok(());
+    }
+
+    log_for_human(
+        "Fixed",
+        &format!(
+            "{n} {fixes} in {m} {files}",
+            n = summary.fixes_applied,
+            fixes = if summary.fixes_applied == 1 { "fix" } else { "fixes" },
+            m = summary.files_fixed.len(),
+            files = if summary.files_fixed.len() == 1 { "file" } else { "files" },
+        ),
+        stream,
+    )?;
+
+    let mut by_crate: Vec<_> = summary.fixes_by_crate.iter().collect();
+    by_crate.sort_by(|a, b| a.0.cmp(b.0));
+    for (krate, fixes) in by_crate {
+        writeln!(
+            stream,
+            "    {:>4} in {}",
+            fixes,
+            krate.as_ref().map(String::as_str).unwrap_or("<unknown>"),
+        )?;
+    }
+
+    if !summary.crates_failed.is_empty() {
+        write_warning(stream)?;
+        stream.set_color(ColorSpec::new().set_bold(true))?;
+        writeln!(stream, "fixing failed in these crates:")?;
+        stream.reset()?;
+        for krate in &summary.crates_failed {
+            writeln!(stream, "  * {}", krate)?;
+        }
+    }
+
+    stream.reset()?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_tallies_fixes_by_file_and_crate() {
+        let mut summary = Summary::default();
+        summary.record(&Message::Fixing {
+            file: "src/lib.rs".to_string(),
+            krate: Some("foo".to_string()),
+            fixes: 2,
+        });
+        summary.record(&Message::Fixing {
+            file: "src/main.rs".to_string(),
+            krate: Some("foo".to_string()),
+            fixes: 1,
+        });
+
+        assert_eq!(summary.fixes_applied, 3);
+        assert_eq!(summary.files_fixed, vec!["src/lib.rs", "src/main.rs"]);
+        assert_eq!(summary.fixes_by_crate[&Some("foo".to_string())], 3);
+    }
+
+    #[test]
+    fn record_tracks_failed_crates() {
+        let mut summary = Summary::default();
+        summary.record(&Message::FixFailed {
+            files: vec!["src/lib.rs".to_string()],
+            krate: Some("foo".to_string()),
+        });
+        summary.record(&Message::ReplaceFailed {
+            file: "src/main.rs".to_string(),
+            krate: Some("bar".to_string()),
+            message: "parse error".to_string(),
+        });
+
+        assert_eq!(summary.crates_failed, vec!["foo".to_string(), "bar".to_string()]);
+    }
+
+    #[test]
+    fn record_ignores_edition_already_set() {
+        let mut summary = Summary::default();
+        summary.record(&Message::EditionAlreadySet {
+            krate: "foo".to_string(),
+        });
+
+        assert_eq!(summary.fixes_applied, 0);
+        assert!(summary.crates_failed.is_empty());
+    }
+
+    #[test]
+    fn print_summary_is_a_no_op_when_nothing_happened() {
+        let summary = Summary::default();
+        let mut stream = output_stream();
+        print_summary(&summary, &mut stream).unwrap();
+    }
+
+    /// The background thread in `Server::start` dedups messages reported by
+    /// concurrent compiles of the same file with a `HashSet<Message>`, which
+    /// only works if equal messages really do compare and hash equal (and
+    /// distinct ones don't).
+    #[test]
+    fn identical_messages_dedup_in_a_hash_set() {
+        let mut seen = HashSet::new();
+        let msg = Message::Fixing {
+            file: "src/lib.rs".to_string(),
+            krate: Some("foo".to_string()),
+            fixes: 1,
+        };
+
+        assert!(seen.insert(msg.clone()));
+        assert!(!seen.insert(msg.clone()), "duplicate message should not re-insert");
+
+        let different_fixes = Message::Fixing {
+            file: "src/lib.rs".to_string(),
+            krate: Some("foo".to_string()),
+            fixes: 2,
+        };
+        assert!(seen.insert(different_fixes), "distinct messages must not collide");
+    }
+
+    /// The derived `Hash`/`Eq` test above only proves `HashSet<Message>`
+    /// *can* dedup; it doesn't prove `Server::start`'s background thread
+    /// actually wires that `seen` check up ahead of `on_message`/`record`.
+    /// Drive a real `Server` through two connections reporting the same
+    /// message to cover the wiring itself.
+    #[test]
+    fn server_dedups_identical_messages_across_connections() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let on_message_calls = Arc::new(AtomicUsize::new(0));
+        let on_message_calls_clone = on_message_calls.clone();
+        let server = Server::new()
+            .unwrap()
+            .start(move |_msg, _stream| {
+                on_message_calls_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        let client = Client::new().expect("Server::start sets the env var Client::new reads");
+        let msg = Message::Fixing {
+            file: "src/lib.rs".to_string(),
+            krate: Some("foo".to_string()),
+            fixes: 1,
+        };
+        client.report(&msg).unwrap();
+        client.report(&msg).unwrap();
+
+        let summary = server.finish();
+        assert_eq!(
+            on_message_calls.load(Ordering::SeqCst),
+            1,
+            "on_message should only fire once for a message reported twice"
+        );
+        assert_eq!(summary.fixes_applied, 1);
+    }
+}
@@ -0,0 +1,259 @@
+//! Detection of a crate's Rust edition, shared between the `cargo fix`
+//! frontend (which needs to pick the right compatibility lint group to
+//! enable) and the rustc-proxy subprocesses it spawns (which need to know
+//! whether the particular crate they're compiling is already done
+//! migrating).
+
+use std::fs;
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+
+/// The Rust editions we know how to migrate between.
+///
+/// New variants should be added here as new editions are stabilized; the
+/// `from_str`, `next`, `lint_group` and `preview_feature` methods are the
+/// only places that need to learn about them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edition {
+    E2015,
+    E2018,
+}
+
+impl Edition {
+    pub fn from_str(s: &str) -> Option<Edition> {
+        match s {
+            "2015" => Some(Edition::E2015),
+            "2018" => Some(Edition::E2018),
+            _ => None,
+        }
+    }
+
+    /// The edition that a crate on `self` can be migrated forward to, if any.
+    pub fn next(self) -> Option<Edition> {
+        match self {
+            Edition::E2015 => Some(Edition::E2018),
+            Edition::E2018 => None,
+        }
+    }
+
+    /// The rustc lint group which reports the lints necessary to migrate a
+    /// crate written for `self` to `self.next()`.
+    pub fn lint_group(self) -> Option<&'static str> {
+        match self {
+            Edition::E2015 => Some("rust-2018-compatibility"),
+            Edition::E2018 => None,
+        }
+    }
+
+    /// The nightly feature gate that must be enabled in a crate's root module
+    /// for `self.next()`'s migration lints to actually fire. `None` once an
+    /// edition's lints no longer need a preview feature to be enabled.
+    pub fn preview_feature(self) -> Option<&'static str> {
+        match self {
+            Edition::E2015 => Some("rust_2018_preview"),
+            Edition::E2018 => None,
+        }
+    }
+}
+
+/// Reads the value of `key` out of the `[package]` table of the manifest at
+/// `manifest_path`, without pulling in a full TOML parser.
+///
+/// This only understands enough TOML to not be fooled by a same-named key
+/// sitting in some other table (`[lib]`, `[[bin]]`, a future
+/// `[workspace.package]`, ...) earlier in the file: it tracks which
+/// `[section]` it's currently inside and only matches `key` while inside
+/// `[package]`. It does not handle inline tables, multi-line strings, or
+/// other TOML features `cargo fix` manifests aren't expected to use.
+pub(crate) fn read_manifest_key(manifest_path: &Path, key: &str) -> Result<Option<String>, Error> {
+    let contents = fs::read_to_string(manifest_path)
+        .with_context(|e| format!("failed to read `{}`: {}", manifest_path.display(), e))?;
+
+    let mut in_package = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+            continue;
+        }
+        if !in_package || !line.starts_with(key) {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let found_key = parts.next().unwrap_or("").trim();
+        if found_key != key {
+            continue;
+        }
+        if let Some(value) = parts.next() {
+            return Ok(Some(value.trim().trim_matches('"').trim_matches('\'').to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether `manifest_path` has no `[package]` table of its own, i.e. it's a
+/// virtual manifest for a workspace whose members (each with their own
+/// `Cargo.toml`) live in subdirectories. Per-crate settings like `edition`
+/// and the preview-feature gate can't be read off a manifest like this.
+pub(crate) fn is_virtual_manifest(manifest_path: &Path) -> Result<bool, Error> {
+    let contents = fs::read_to_string(manifest_path)
+        .with_context(|e| format!("failed to read `{}`: {}", manifest_path.display(), e))?;
+
+    Ok(!contents.lines().any(|line| line.trim() == "[package]"))
+}
+
+/// Figure out which edition a crate is currently written for by reading the
+/// `edition` key out of its manifest. Crates with no `edition` key are on the
+/// 2015 edition, same as `cargo` itself assumes.
+pub fn detect_current_edition(manifest_path: &Path) -> Result<Edition, Error> {
+    let value = match read_manifest_key(manifest_path, "edition")? {
+        Some(value) => value,
+        None => return Ok(Edition::E2015),
+    };
+
+    Edition::from_str(&value).ok_or_else(|| {
+        format_err!(
+            "failed to parse `edition` key `{}` in `{}`",
+            value,
+            manifest_path.display()
+        )
+    })
+}
+
+/// Checks whether the crate rooted next to `manifest_path` has enabled the
+/// preview feature (if any) required for `edition`'s migration lints to
+/// fire, by scanning its crate root (`src/lib.rs` or `src/main.rs`) for the
+/// feature's name. Any occurrence of the name counts; whether it actually
+/// sits inside a `#![feature(...)]` attribute is not checked.
+pub fn has_preview_feature_enabled(manifest_path: &Path, edition: Edition) -> bool {
+    let feature = match edition.preview_feature() {
+        Some(feature) => feature,
+        None => return true,
+    };
+
+    let src_dir = manifest_path.parent().unwrap_or_else(|| Path::new(".")).join("src");
+    for candidate in &["lib.rs", "main.rs"] {
+        if let Ok(contents) = fs::read_to_string(src_dir.join(candidate)) {
+            if contents.contains(feature) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Writes `contents` to a fresh file under the system temp dir and
+    /// returns its path, so tests don't need a real crate checked out on
+    /// disk to exercise the manifest-reading helpers.
+    fn temp_manifest(contents: &str) -> ::std::path::PathBuf {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let path = env::temp_dir().join(format!(
+            "cargo-fix-test-{}-{}.toml",
+            ::std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut f = File::create(&path).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn from_str_recognizes_known_editions() {
+        assert_eq!(Edition::from_str("2015"), Some(Edition::E2015));
+        assert_eq!(Edition::from_str("2018"), Some(Edition::E2018));
+        assert_eq!(Edition::from_str("2021"), None);
+    }
+
+    #[test]
+    fn next_stops_at_the_newest_known_edition() {
+        assert_eq!(Edition::E2015.next(), Some(Edition::E2018));
+        assert_eq!(Edition::E2018.next(), None);
+    }
+
+    #[test]
+    fn lint_group_is_only_set_for_editions_with_a_successor() {
+        assert_eq!(Edition::E2015.lint_group(), Some("rust-2018-compatibility"));
+        assert_eq!(Edition::E2018.lint_group(), None);
+    }
+
+    #[test]
+    fn detect_current_edition_defaults_to_2015_with_no_edition_key() {
+        let path = temp_manifest("[package]\nname = \"foo\"\n");
+        assert_eq!(detect_current_edition(&path).unwrap(), Edition::E2015);
+    }
+
+    #[test]
+    fn detect_current_edition_reads_the_edition_key() {
+        let path = temp_manifest("[package]\nname = \"foo\"\nedition = \"2018\"\n");
+        assert_eq!(detect_current_edition(&path).unwrap(), Edition::E2018);
+    }
+
+    #[test]
+    fn read_manifest_key_ignores_same_named_keys_outside_package() {
+        // A `name` key under `[lib]` that comes before `[package]` shouldn't
+        // be mistaken for the package's own name.
+        let path = temp_manifest("[lib]\nname = \"not_the_crate\"\n\n[package]\nname = \"foo\"\n");
+        assert_eq!(
+            read_manifest_key(&path, "name").unwrap(),
+            Some("foo".to_string())
+        );
+    }
+
+    #[test]
+    fn read_manifest_key_returns_none_when_key_is_absent() {
+        let path = temp_manifest("[package]\nname = \"foo\"\n");
+        assert_eq!(read_manifest_key(&path, "edition").unwrap(), None);
+    }
+
+    /// Creates a fresh directory with a `src/<root>` file containing
+    /// `contents`, and returns the path a `Cargo.toml` would live at next to
+    /// it (the file itself is never read by `has_preview_feature_enabled`).
+    fn temp_crate_root(root: &str, contents: &str) -> ::std::path::PathBuf {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = env::temp_dir().join(format!(
+            "cargo-fix-test-crate-{}-{}",
+            ::std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let src_dir = dir.join("src");
+        ::std::fs::create_dir_all(&src_dir).unwrap();
+        let mut f = File::create(src_dir.join(root)).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        dir.join("Cargo.toml")
+    }
+
+    #[test]
+    fn has_preview_feature_enabled_true_when_edition_needs_none() {
+        let manifest_path = temp_crate_root("lib.rs", "");
+        assert!(has_preview_feature_enabled(&manifest_path, Edition::E2018));
+    }
+
+    #[test]
+    fn has_preview_feature_enabled_checks_the_crate_root_for_the_gate() {
+        let manifest_path = temp_crate_root("lib.rs", "#![feature(rust_2018_preview)]\n");
+        assert!(has_preview_feature_enabled(&manifest_path, Edition::E2015));
+    }
+
+    #[test]
+    fn has_preview_feature_enabled_false_when_gate_missing() {
+        let manifest_path = temp_crate_root("lib.rs", "fn main() {}\n");
+        assert!(!has_preview_feature_enabled(&manifest_path, Edition::E2015));
+    }
+
+    #[test]
+    fn has_preview_feature_enabled_checks_main_rs_too() {
+        let manifest_path = temp_crate_root("main.rs", "#![feature(rust_2018_preview)]\n");
+        assert!(has_preview_feature_enabled(&manifest_path, Edition::E2015));
+    }
+}
@@ -0,0 +1,336 @@
+//! The other half of `cargo fix`: this module is what actually runs when
+//! `cargo fix` re-invokes itself as `RUSTC` (with `__CARGO_FIX_NOW_RUSTC` set
+//! in the environment). It shells out to the real compiler, collects any
+//! suggestions it emits, applies the machine-applicable ones to disk,
+//! verifies the result still compiles (backing out if not), and reports
+//! what happened back to the parent `cargo fix` process.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use failure::{Error, ResultExt};
+use rustfix::diagnostics::Diagnostic;
+use rustfix::{self, CodeFix};
+
+use diagnostics::{Client, Message};
+use edition;
+use lock;
+
+/// We re-apply and re-verify suggestions in a loop, since fixing one
+/// suggestion can surface another (e.g. a macro expansion that's only
+/// visible once an earlier error is resolved). This bounds how many times
+/// we'll go around that loop for a single compilation.
+const MAX_ITERATIONS: u32 = 4;
+
+pub fn fix_rustc() -> Result<(), Error> {
+    let rustc = env::var_os("RUSTC_ORIGINAL").unwrap_or_else(|| "rustc".into());
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let client = Client::new();
+    let lock_client = lock::Client::new();
+    let primary = is_primary_package()?;
+
+    // In edition-upgrade mode, each subprocess compiles one crate of the
+    // workspace; a crate that's individually already on the edition being
+    // prepared for would never see its migration lints fire, so flag it
+    // rather than leaving the user to wonder why nothing changed. Only do
+    // this for crates we were actually asked to fix: most path dependencies
+    // in a workspace are already on a newer edition and this warning would
+    // otherwise just be noise about crates the user has no control over.
+    if primary && env::var("__CARGO_FIX_EDITION_UPGRADE").is_ok() {
+        if let Some(ref client) = client {
+            if let Some(krate) = check_edition_already_set()? {
+                let _ = client.report(&Message::EditionAlreadySet { krate });
+            }
+        }
+    }
+
+    let mut output = run_rustc(&rustc, &args)?;
+
+    if !output.status.success() || !primary {
+        print_stderr(&output);
+        super::exit_with(output.status);
+    }
+
+    let broken_code_ok = env::var("__CARGO_FIX_BROKEN_CODE").is_ok();
+    let krate = env::var("CARGO_PKG_NAME").ok();
+
+    // Snapshots of each touched file's contents as of the last iteration we
+    // reverified didn't introduce new errors, so we can restore to that
+    // "last known good" state if a later iteration's fixes don't compile.
+    // These are rolled forward after every successful reverify rather than
+    // left at the very first snapshot, so a bad fix surfacing a couple of
+    // iterations in only backs out that iteration's changes, not ones
+    // already confirmed safe.
+    let mut originals: HashMap<PathBuf, String> = HashMap::new();
+    // Total number of suggestions successfully applied to each file, across
+    // every iteration, for the final report.
+    let mut fix_counts: HashMap<PathBuf, u32> = HashMap::new();
+
+    for _ in 0..MAX_ITERATIONS {
+        let by_file = suggestions_by_file(&output);
+        if by_file.is_empty() {
+            break;
+        }
+
+        let mut changed_any = false;
+        let mut touched_this_iteration = Vec::new();
+        for (file, suggestions) in &by_file {
+            // `cargo check` routinely compiles the same source file under
+            // several targets/feature sets concurrently, so another
+            // subprocess may be snapshotting/applying/restoring this exact
+            // file at the same time. Hold the file lock across the whole
+            // read-modify-write so the two never interleave.
+            let _guard = lock_client.as_ref().map(lock::Client::acquire).transpose()?;
+
+            originals
+                .entry(file.clone())
+                .or_insert_with(|| fs::read_to_string(file).unwrap_or_default());
+            touched_this_iteration.push(file.clone());
+
+            let before = fs::read_to_string(file).unwrap_or_default();
+            let applied = match apply_suggestions(file, suggestions) {
+                Ok(applied) => applied,
+                Err(e) => {
+                    if let Some(ref client) = client {
+                        let _ = client.report(&Message::ReplaceFailed {
+                            file: file.display().to_string(),
+                            krate: krate.clone(),
+                            message: e.to_string(),
+                        });
+                    }
+                    continue;
+                }
+            };
+            let after = fs::read_to_string(file).unwrap_or_default();
+            if after != before {
+                changed_any = true;
+                *fix_counts.entry(file.clone()).or_insert(0) += applied;
+            }
+        }
+
+        // Nothing actually changed on disk this round (every suggestion
+        // failed to apply or was a no-op), so there's no point reverifying
+        // or looping any further.
+        if !changed_any {
+            break;
+        }
+
+        let verify = run_rustc(&rustc, &args)?;
+        if !broken_code_ok && introduced_new_errors(&output, &verify) {
+            for (file, original) in &originals {
+                let _guard = lock_client.as_ref().map(lock::Client::acquire).transpose()?;
+                let _ = fs::write(file, original);
+            }
+            if let Some(ref client) = client {
+                let _ = client.report(&Message::FixFailed {
+                    files: originals.keys().map(|f| f.display().to_string()).collect(),
+                    krate: krate.clone(),
+                });
+            }
+            print_stderr(&output);
+            super::exit_with(output.status);
+        }
+
+        // This iteration's fixes reverified clean, so they become the new
+        // restore point rather than leaving `originals` pinned to the very
+        // first snapshot taken.
+        for file in &touched_this_iteration {
+            let _guard = lock_client.as_ref().map(lock::Client::acquire).transpose()?;
+            if let Ok(contents) = fs::read_to_string(file) {
+                originals.insert(file.clone(), contents);
+            }
+        }
+
+        output = verify;
+    }
+
+    for (file, fixes) in &fix_counts {
+        if let Some(ref client) = client {
+            let _ = client.report(&Message::Fixing {
+                file: file.display().to_string(),
+                krate: krate.clone(),
+                fixes: *fixes,
+            });
+        }
+    }
+
+    print_stderr(&output);
+    super::exit_with(output.status);
+}
+
+fn run_rustc(rustc: &::std::ffi::OsStr, args: &[::std::ffi::OsString]) -> Result<Output, Error> {
+    Command::new(rustc)
+        .args(args)
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|e| format!("failed to spawn `{}`: {}", rustc.to_string_lossy(), e))
+        .map_err(Into::into)
+}
+
+fn print_stderr(output: &Output) {
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        eprintln!("{}", line);
+    }
+}
+
+fn suggestions_by_file(output: &Output) -> HashMap<PathBuf, Vec<rustfix::Suggestion>> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let suggestions = stderr
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Diagnostic>(line).ok())
+        .filter_map(|diag| rustfix::collect_suggestions(&diag, &Default::default()));
+
+    let mut by_file: HashMap<PathBuf, Vec<_>> = HashMap::new();
+    for suggestion in suggestions {
+        for solution in &suggestion.solutions {
+            for replacement in &solution.replacements {
+                by_file
+                    .entry(PathBuf::from(&replacement.snippet.file_name))
+                    .or_insert_with(Vec::new)
+                    .push(suggestion.clone());
+                break;
+            }
+        }
+    }
+    by_file
+}
+
+/// Whether `after` reports more compiler errors than `before` did, meaning
+/// the fixes we just applied broke something that wasn't broken already.
+fn introduced_new_errors(before: &Output, after: &Output) -> bool {
+    fn error_count(output: &Output) -> usize {
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Diagnostic>(line).ok())
+            .filter(|diag| diag.level == "error")
+            .count()
+    }
+
+    error_count(after) > error_count(before)
+}
+
+/// Whether the crate currently being compiled is one `cargo fix` was asked
+/// to fix. `__CARGO_FIX_PRIMARY_PACKAGES` is unset when `--fix-deps` was
+/// passed, meaning every compiled crate is fair game.
+fn is_primary_package() -> Result<bool, Error> {
+    let primary = match env::var("__CARGO_FIX_PRIMARY_PACKAGES") {
+        Ok(primary) => primary,
+        Err(_) => return Ok(true),
+    };
+    let krate = env::var("CARGO_PKG_NAME").unwrap_or_default();
+    Ok(primary.split(',').any(|name| name == krate))
+}
+
+/// Returns `Some(name)` of the crate currently being compiled if it's
+/// already on the edition we're preparing for, using the `CARGO_MANIFEST_DIR`
+/// and `CARGO_PKG_NAME` environment variables Cargo sets for every rustc
+/// invocation.
+fn check_edition_already_set() -> Result<Option<String>, Error> {
+    let manifest_dir = match env::var("CARGO_MANIFEST_DIR") {
+        Ok(dir) => dir,
+        Err(_) => return Ok(None),
+    };
+    let manifest_path = PathBuf::from(manifest_dir).join("Cargo.toml");
+    let current = edition::detect_current_edition(&manifest_path)?;
+    if current.next().is_some() {
+        return Ok(None);
+    }
+
+    let krate = env::var("CARGO_PKG_NAME").unwrap_or_else(|_| "<unknown>".into());
+    Ok(Some(krate))
+}
+
+/// Applies as many of `suggestions` to `file` as cleanly apply (e.g. a
+/// suggestion with a span overlapping an earlier one is simply skipped), and
+/// returns how many actually landed, for an accurate fix count.
+fn apply_suggestions(file: &PathBuf, suggestions: &[rustfix::Suggestion]) -> Result<u32, Error> {
+    let code = fs::read_to_string(file).with_context(|e| format!("failed to read `{}`: {}", file.display(), e))?;
+    let mut fix = CodeFix::new(&code);
+    let mut applied = 0;
+    for suggestion in suggestions.iter().rev() {
+        if fix.apply(suggestion).is_ok() {
+            applied += 1;
+        }
+    }
+    let fixed = fix.finish()?;
+
+    let mut f = fs::File::create(file).with_context(|e| format!("failed to write `{}`: {}", file.display(), e))?;
+    f.write_all(fixed.as_bytes())?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    /// `Output` only comes from actually running a process on stable Rust,
+    /// so build one by hand out of captured "stderr" for these tests.
+    fn output_with_stderr(stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    fn diagnostic_line(level: &str, with_suggestion: bool) -> String {
+        let suggested_replacement = if with_suggestion { "\"\"" } else { "null" };
+        format!(
+            r#"{{"message":"unused import","code":null,"level":"{level}","spans":[{{"file_name":"src/lib.rs","byte_start":0,"byte_end":8,"line_start":1,"line_end":1,"column_start":1,"column_end":9,"is_primary":true,"text":[{{"text":"use foo;","highlight_start":1,"highlight_end":9}}],"label":null,"suggested_replacement":{suggested_replacement},"suggestion_applicability":"MachineApplicable","expansion":null}}],"children":[],"rendered":null}}"#,
+            level = level,
+            suggested_replacement = suggested_replacement,
+        )
+    }
+
+    #[test]
+    fn suggestions_by_file_groups_by_the_suggestions_file_name() {
+        let output = output_with_stderr(&diagnostic_line("warning", true));
+        let by_file = suggestions_by_file(&output);
+        assert_eq!(by_file.len(), 1);
+        assert!(by_file.contains_key(&PathBuf::from("src/lib.rs")));
+    }
+
+    #[test]
+    fn suggestions_by_file_skips_diagnostics_with_no_suggestion() {
+        let output = output_with_stderr(&diagnostic_line("warning", false));
+        assert!(suggestions_by_file(&output).is_empty());
+    }
+
+    #[test]
+    fn introduced_new_errors_compares_error_counts() {
+        let clean = output_with_stderr(&diagnostic_line("warning", true));
+        let broken = output_with_stderr(&diagnostic_line("error", true));
+        assert!(introduced_new_errors(&clean, &broken));
+        assert!(!introduced_new_errors(&broken, &clean));
+        assert!(!introduced_new_errors(&broken, &broken));
+    }
+
+    #[test]
+    fn is_primary_package_true_when_no_filter_is_set() {
+        env::remove_var("__CARGO_FIX_PRIMARY_PACKAGES");
+        assert!(is_primary_package().unwrap());
+    }
+
+    #[test]
+    fn is_primary_package_checks_the_crate_name_against_the_filter() {
+        env::set_var("__CARGO_FIX_PRIMARY_PACKAGES", "is_primary_package_checks_the_crate_name_against_the_filter_foo,bar");
+
+        env::set_var(
+            "CARGO_PKG_NAME",
+            "is_primary_package_checks_the_crate_name_against_the_filter_foo",
+        );
+        assert!(is_primary_package().unwrap());
+
+        env::set_var("CARGO_PKG_NAME", "baz");
+        assert!(!is_primary_package().unwrap());
+
+        env::remove_var("__CARGO_FIX_PRIMARY_PACKAGES");
+        env::remove_var("CARGO_PKG_NAME");
+    }
+}
@@ -0,0 +1,66 @@
+//! A tiny IPC lock used to serialize access to a given source file across
+//! the many rustc-proxy subprocesses `cargo check` may spawn concurrently,
+//! so two processes never try to rewrite the same file at once.
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+use failure::{Error, ResultExt};
+
+const ENV: &str = "__CARGO_FIX_LOCK_ENDPOINT";
+
+pub struct Server {
+    listener: TcpListener,
+}
+
+pub struct Client {
+    addr: String,
+}
+
+impl Server {
+    pub fn new() -> Result<Server, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("failed to bind lock server")?;
+        Ok(Server { listener })
+    }
+
+    pub fn start(self) -> Result<JoinHandle<()>, Error> {
+        let addr = self.listener.local_addr()?;
+        env::set_var(ENV, addr.to_string());
+
+        Ok(thread::spawn(move || {
+            for conn in self.listener.incoming() {
+                let mut conn = match conn {
+                    Ok(conn) => conn,
+                    Err(_) => continue,
+                };
+                // Accepting connections one at a time off a single-threaded
+                // loop is what actually serializes access: a client's
+                // `connect` can complete before we `accept` it, so
+                // `Client::acquire` waits for the byte we write here, which
+                // we only send to one accepted client at a time. We then hold
+                // *this* one open until the client is done with its critical
+                // section, blocking on reading it to EOF (the client closing
+                // its end of the stream, once `Client::acquire`'s returned
+                // `TcpStream` is dropped) before moving on to the next
+                // connection.
+                let _ = conn.write_all(&[1]);
+                let _ = conn.read_to_end(&mut Vec::new());
+            }
+        }))
+    }
+}
+
+impl Client {
+    pub fn new() -> Option<Client> {
+        env::var(ENV).ok().map(|addr| Client { addr })
+    }
+
+    pub fn acquire(&self) -> Result<TcpStream, Error> {
+        let mut conn = TcpStream::connect(&self.addr).context("failed to connect to lock server")?;
+        let mut buf = [0u8; 1];
+        conn.read_exact(&mut buf).context("failed to acquire lock from lock server")?;
+        Ok(conn)
+    }
+}
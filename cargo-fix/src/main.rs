@@ -0,0 +1,47 @@
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+extern crate clap;
+extern crate env_logger;
+extern crate rustfix;
+extern crate serde_json;
+extern crate termcolor;
+
+use std::env;
+use std::process::{self, ExitStatus};
+
+use failure::Fail;
+
+mod cli;
+mod diagnostics;
+mod edition;
+mod fix;
+mod lock;
+mod vcs;
+
+fn main() {
+    env_logger::init();
+
+    let result = if env::var("__CARGO_FIX_NOW_RUSTC").is_ok() {
+        fix::fix_rustc()
+    } else {
+        cli::run()
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        for cause in e.iter_causes() {
+            eprintln!("caused by: {}", cause);
+        }
+        process::exit(1);
+    }
+}
+
+/// Exits the process with the same status code `status` carried, preserving
+/// the exit code of a spawned subprocess (e.g. the real `cargo check`).
+pub fn exit_with(status: ExitStatus) -> ! {
+    process::exit(status.code().unwrap_or(1));
+}
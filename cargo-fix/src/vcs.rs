@@ -0,0 +1,50 @@
+//! Detection of whether the current directory is tracked by (and clean
+//! under) a version control system, so we can warn users before rewriting
+//! their source out from under them.
+
+use std::process::Command;
+
+use failure::Error;
+
+pub struct VersionControl {
+    kind: Option<&'static str>,
+}
+
+impl VersionControl {
+    pub fn new() -> VersionControl {
+        let kind = if Command::new("git")
+            .arg("rev-parse")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+        {
+            Some("git")
+        } else {
+            None
+        };
+        VersionControl { kind }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.kind.is_some()
+    }
+
+    /// Returns `Some(output)` with the VCS's status output if the working
+    /// directory has uncommitted changes, `None` if it's clean.
+    pub fn is_dirty(&self) -> Result<Option<Vec<u8>>, Error> {
+        match self.kind {
+            Some("git") => {
+                let output = Command::new("git")
+                    .arg("status")
+                    .arg("--porcelain")
+                    .output()?;
+                if output.stdout.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(output.stdout))
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}